@@ -1,23 +1,31 @@
 use std::fmt::Display;
 use std::fs::File;
 use std::io::BufReader;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::thread;
 use std::{net::TcpListener, io::Write};
 use std::net::TcpStream;
 use std::io::prelude::*;
 use anyhow::{anyhow, Result};
 
+/// Upper bound on a request body we're willing to buffer in memory, enforced for every
+/// upload regardless of `Expect: 100-continue` - that header just lets us reject before
+/// the body arrives instead of after.
+const MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
 enum Verb {
     Get,
-    Post
+    Post,
+    Head
 }
 
 impl Display for Verb {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Verb::Get => write!(f, "GET")?,
-            Verb::Post => write!(f, "POST")?
+            Verb::Post => write!(f, "POST")?,
+            Verb::Head => write!(f, "HEAD")?
         }
 
         Ok(())
@@ -31,6 +39,7 @@ impl TryFrom<&str> for Verb {
         match value {
             "GET" => Ok(Verb::Get),
             "POST" => Ok(Verb::Post),
+            "HEAD" => Ok(Verb::Head),
             _ => Err(anyhow!("Unknown verb {value}"))
         }
     }
@@ -102,6 +111,19 @@ impl Headers {
     }
 }
 
+/// Named path segments captured by a route pattern, e.g. `/files/:name` binds `name`.
+struct Params(Vec<(String, String)>);
+
+impl Params {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    fn add(&mut self, key: String, value: String) {
+        self.0.push((key, value));
+    }
+}
+
 impl Header {
     fn is_header(line: &str) -> bool {
         let components: Vec<&str>  = line.splitn(2, ':').collect();
@@ -110,6 +132,44 @@ impl Header {
     }
 }
 
+enum RequestRange {
+    From(usize),
+    Full(usize, usize),
+    Suffix(usize)
+}
+
+impl TryFrom<&str> for RequestRange {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> std::result::Result<Self, Self::Error> {
+        let value = value.strip_prefix("bytes=").ok_or_else(|| anyhow!("Range header missing 'bytes=' prefix"))?;
+        let (start, end) = value.split_once('-').ok_or_else(|| anyhow!("Range header missing '-'"))?;
+
+        match (start, end) {
+            ("", suffix) => Ok(RequestRange::Suffix(suffix.parse()?)),
+            (start, "") => Ok(RequestRange::From(start.parse()?)),
+            (start, end) => Ok(RequestRange::Full(start.parse()?, end.parse()?))
+        }
+    }
+}
+
+impl RequestRange {
+    /// Resolves this range against a resource of `len` bytes, returning an inclusive
+    /// `(start, end)` byte range, or `None` if the range is not satisfiable.
+    fn resolve(&self, len: usize) -> Option<(usize, usize)> {
+        if len == 0 {
+            return None;
+        }
+
+        match *self {
+            RequestRange::From(start) if start < len => Some((start, len - 1)),
+            RequestRange::Full(start, end) if start < len && start <= end => Some((start, end.min(len - 1))),
+            RequestRange::Suffix(n) if n > 0 => Some((len.saturating_sub(n), len - 1)),
+            _ => None
+        }
+    }
+}
+
 fn save_file(path: PathBuf, contents: &[u8]) -> Result<usize> {
     let mut file = File::create("foo.txt")?;
     file.write_all(contents);
@@ -117,16 +177,149 @@ fn save_file(path: PathBuf, contents: &[u8]) -> Result<usize> {
     Ok(contents.len())
 }
 
+/// Decodes `%XX` percent-escapes in a URL path component, e.g. `%20` -> ` `.
+fn percent_decode(value: &str) -> Result<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = value.get(i + 1..i + 3).ok_or_else(|| anyhow!("Truncated percent-escape in path"))?;
+            let byte = u8::from_str_radix(hex, 16)?;
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    Ok(String::from_utf8(decoded)?)
+}
+
+/// Percent-encodes a single path segment so it round-trips through a URL unchanged, e.g.
+/// a file named `a?b.txt` links to `a%3Fb.txt` rather than truncating at the `?` as a query
+/// string delimiter.
+fn percent_encode(value: &str) -> String {
+    value.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+/// Escapes `&`, `<`, `>` and `"` so untrusted text can't break out of HTML markup or
+/// an attribute it's interpolated into.
+fn html_escape(value: &str) -> String {
+    value.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Builds an HTML index page listing the entries of `dir`, sorted by name.
+fn render_directory_index(dir: &PathBuf, request_path: &str) -> Result<String> {
+    let mut entries: Vec<String> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect();
+    entries.sort();
+
+    let prefix = if request_path.ends_with('/') { request_path.to_string() } else { format!("{request_path}/") };
+    let links: String = entries.iter()
+        .map(|name| {
+            let href = html_escape(&format!("{prefix}{}", percent_encode(name)));
+            let text = html_escape(name);
+            format!("<li><a href=\"{href}\">{text}</a></li>")
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    Ok(format!("<html><body><ul>\n{links}\n</ul></body></html>"))
+}
+
 struct Body(Vec<u8>);
 
+/// A response under construction; route handlers build one of these via the `status`/
+/// `header`/`body` builder methods, then a final pass (encoding, `Content-Length`)
+/// serializes it to bytes. `Content-Length` is intentionally not a builder method — it's
+/// always derived from the body at serialization time, so handlers can't let it drift.
+struct Response {
+    status: u16,
+    reason: &'static str,
+    headers: Headers,
+    body: Body,
+    suppress_body: bool
+}
+
+impl Response {
+    fn status(status: u16, reason: &'static str) -> Response {
+        Response { status, reason, headers: Headers(vec![]), body: Body(vec![]), suppress_body: false }
+    }
+
+    fn header(mut self, key: &str, value: &str) -> Response {
+        self.headers.add(Header { key: key.to_string(), value: value.to_string() });
+        self
+    }
+
+    fn body(mut self, body: Vec<u8>) -> Response {
+        self.body = Body(body);
+        self
+    }
+
+    /// Keeps every header (including `Content-Length` as it would be for the full body)
+    /// but omits the body bytes themselves - what a HEAD response needs.
+    fn discard_body(mut self) -> Response {
+        self.suppress_body = true;
+        self
+    }
+
+    // Body bytes aren't guaranteed to be valid UTF-8 (gzip, range slices, raw files), so a
+    // `Display` impl can't losslessly render the wire format; this produces it directly.
+    fn serialize(self) -> Vec<u8> {
+        let mut out = format!("HTTP/1.1 {0} {1}\r\n", self.status, self.reason).into_bytes();
+
+        for header in &self.headers.0 {
+            out.extend_from_slice(format!("{0}: {1}\r\n", header.key, header.value).as_bytes());
+        }
+        out.extend_from_slice(format!("Content-Length: {0}\r\n\r\n", self.body.0.len()).as_bytes());
+        if !self.suppress_body {
+            out.extend_from_slice(&self.body.0);
+        }
+
+        out
+    }
+}
+
+/// Picks the first `Accept-Encoding` coding we support (currently just `gzip`). Codings are
+/// case-insensitive and may carry a `;q=...` parameter (e.g. `gzip;q=1.0`), which we ignore -
+/// we don't support enough codings for quality-value ranking to matter.
+fn negotiate_encoding(accept_encoding: &str) -> Option<&'static str> {
+    accept_encoding.split(',')
+        .map(|c| c.split(';').next().unwrap_or("").trim().to_ascii_lowercase())
+        .find(|c| c == "gzip")
+        .map(|_| "gzip")
+}
+
+fn gzip_compress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
 struct Request(StartLine, Headers, Option<Body>);
 
-fn read_stream(stream: &mut TcpStream) -> Result<Request> {
-    let mut reader = BufReader::new(stream);
-    let mut buf = String::with_capacity(50); 
+/// Reads one request off `reader`. Returns `Ok(None)` on a clean EOF (the client closed
+/// the connection before sending a new start line), which ends the connection's request loop.
+fn read_stream(reader: &mut BufReader<TcpStream>) -> Result<Option<Request>> {
+    let mut buf = String::with_capacity(50);
 
-    // Read the first line in
-    reader.read_line(&mut buf)?;
+    // Read the first line in. Zero bytes means the peer closed the connection.
+    if reader.read_line(&mut buf)? == 0 {
+        return Ok(None);
+    }
     let start_line = StartLine::try_from(buf.as_str())?;
     buf.clear();
 
@@ -148,6 +341,20 @@ fn read_stream(stream: &mut TcpStream) -> Result<Request> {
     let body = match headers.get("Content-Length") {
         Some(content_length) => {
             let size: usize = content_length.parse()?;
+            let expects_continue = headers.get("Expect").map(|e| e.eq_ignore_ascii_case("100-continue")).unwrap_or(false);
+
+            if size > MAX_BODY_SIZE {
+                // Expect: 100-continue lets us reject before the client ever sends the body.
+                // Without it the body may already be on the wire, but we still refuse to
+                // allocate for or process an oversized payload.
+                reader.get_mut().write_all(b"HTTP/1.1 413 Payload Too Large\r\n\r\n")?;
+                return Ok(None);
+            }
+
+            if expects_continue {
+                reader.get_mut().write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+            }
+
             let mut body = vec![0; size];
             reader.read_exact(&mut body)?;
             Some(Body(body))
@@ -155,49 +362,228 @@ fn read_stream(stream: &mut TcpStream) -> Result<Request> {
         None => None
     };
 
-    Ok(Request(start_line, headers, body))
+    Ok(Some(Request(start_line, headers, body)))
 }
 
-fn handle_request(mut stream: &mut TcpStream, opts: Args) -> Result<String> {
-    let Request ( start_line, headers, body ) = read_stream(&mut stream)?;
-    let StartLine { verb, path } = start_line;
+type Handler = fn(&Request, &Params, &Args) -> Result<Response>;
 
-    let response = match (verb, path.as_str(), body) {
-        (Verb::Get, p, _) if p.starts_with("/echo/") => {
-            let to_echo = p.strip_prefix("/echo/").unwrap(); // We just tested above
-            format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {0}\r\n\r\n{to_echo}", to_echo.len())
-        },
-        (Verb::Post, p, Some(b)) if p.starts_with("/files/") => {
-            let file_name = path.strip_prefix("/files/").unwrap();
-            let file_path = opts.directory.unwrap().join(file_name);
-            let mut file = File::create(file_path)?;
-            let content_length = headers.get("Content-Length").unwrap();
-            let size: usize = content_length.parse()?;
-            file.write_all(&b.0)?;
-            "HTTP/1.1 201 Created\r\n\r\n201 Created".to_string()
+/// One registered `(Verb, path-pattern)` -> handler mapping. Patterns are `/`-separated;
+/// a segment starting with `:` binds that path segment (or, if it's the final segment,
+/// the whole remainder of the path) into `Params` under that name.
+struct Route {
+    verb: Verb,
+    pattern: &'static str,
+    handler: Handler
+}
+
+struct Router(Vec<Route>);
+
+impl Router {
+    fn new() -> Router {
+        Router(vec![
+            Route { verb: Verb::Get, pattern: "/echo/:msg", handler: echo_handler },
+            Route { verb: Verb::Post, pattern: "/files/:name", handler: upload_handler },
+            Route { verb: Verb::Get, pattern: "/files/:name", handler: serve_file_handler },
+            Route { verb: Verb::Get, pattern: "/user-agent", handler: user_agent_handler },
+            Route { verb: Verb::Get, pattern: "/", handler: root_handler }
+        ])
+    }
+
+    fn dispatch(&self, request: &Request, opts: &Args) -> Result<Response> {
+        let StartLine { verb, path } = &request.0;
+
+        // HEAD reuses whatever route GET would have matched, then the body is dropped
+        // after the fact so the handler doesn't need a HEAD-specific code path.
+        let routed_verb = if *verb == Verb::Head { Verb::Get } else { *verb };
+
+        for route in &self.0 {
+            if route.verb != routed_verb {
+                continue;
+            }
+            if let Some(params) = match_pattern(route.pattern, path) {
+                let response = (route.handler)(request, &params, opts)?;
+                return Ok(if *verb == Verb::Head { response.discard_body() } else { response });
+            }
+        }
+
+        let response = Response::status(404, "Not Found").body(b"404 Not Found".to_vec());
+        Ok(if *verb == Verb::Head { response.discard_body() } else { response })
+    }
+}
+
+fn match_pattern(pattern: &str, path: &str) -> Option<Params> {
+    let pattern_segments: Vec<&str> = pattern.split('/').collect();
+    let rest: String;
+    let mut path_segments: Vec<&str> = path.split('/').collect();
+
+    // A trailing `:name` segment captures the rest of the path, slashes and all - this is
+    // what lets `/files/:name` also serve nested directory listings.
+    if pattern_segments.last().map_or(false, |s| s.starts_with(':')) {
+        let prefix_len = pattern_segments.len() - 1;
+        if path_segments.len() < prefix_len {
+            return None;
+        }
+        rest = path_segments.split_off(prefix_len).join("/");
+        path_segments.push(&rest);
+    }
+
+    if pattern_segments.len() != path_segments.len() {
+        return None;
+    }
+
+    let mut params = Params(vec![]);
+    for (p, s) in pattern_segments.iter().zip(path_segments.iter()) {
+        match p.strip_prefix(':') {
+            Some(name) => params.add(name.to_string(), s.to_string()),
+            None if p == s => {},
+            None => return None
+        }
+    }
+    Some(params)
+}
+
+fn echo_handler(_request: &Request, params: &Params, _opts: &Args) -> Result<Response> {
+    let to_echo = params.get("msg").unwrap_or("");
+    Ok(Response::status(200, "OK").header("Content-Type", "text/plain").body(to_echo.as_bytes().to_vec()))
+}
+
+fn upload_handler(request: &Request, params: &Params, opts: &Args) -> Result<Response> {
+    let Request(_, _, body) = request;
+
+    let Some(directory) = opts.directory.as_ref() else {
+        return Ok(Response::status(404, "Not Found").body(b"404 Not Found".to_vec()));
+    };
+
+    let raw_name = params.get("name").unwrap_or("");
+    let file_name = percent_decode(raw_name)?;
+
+    if file_name.split('/').any(|segment| segment == "..") || Path::new(&file_name).is_absolute() {
+        return Ok(Response::status(404, "Not Found").body(b"404 Not Found".to_vec()));
+    }
+
+    let file_path = directory.join(&file_name);
+    let mut file = File::create(file_path)?;
+    if let Some(b) = body {
+        file.write_all(&b.0)?;
+    }
+    Ok(Response::status(201, "Created").body(b"201 Created".to_vec()))
+}
+
+fn serve_file_handler(request: &Request, params: &Params, opts: &Args) -> Result<Response> {
+    let Request(StartLine { path, .. }, headers, _) = request;
+
+    let Some(directory) = opts.directory.as_ref() else {
+        return Ok(Response::status(404, "Not Found").body(b"404 Not Found".to_vec()));
+    };
+
+    let raw_name = params.get("name").unwrap_or("");
+    let file_name = percent_decode(raw_name)?;
+
+    if file_name.split('/').any(|segment| segment == "..") || Path::new(&file_name).is_absolute() {
+        return Ok(Response::status(404, "Not Found").body(b"404 Not Found".to_vec()));
+    }
+
+    let file_path = directory.join(&file_name);
+    match std::fs::metadata(&file_path) {
+        Ok(metadata) if metadata.is_dir() => {
+            let body = render_directory_index(&file_path, path)?;
+            Ok(Response::status(200, "OK").header("Content-Type", "text/html").body(body.into_bytes()))
         },
-        (Verb::Get, p, _) if opts.directory.is_some() && p.starts_with("/files/")  => {
-            let file_name = path.strip_prefix("/files/").unwrap();
-            let file_path = opts.directory.unwrap().join(file_name);
-            match std::fs::metadata(&file_path) {
-                Ok(_) => {
-                    let contents = std::fs::read_to_string(file_path)?;
-                    format!("HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {0}\r\n\r\n{1}", contents.len(), contents)
+        Ok(_) => {
+            let contents = std::fs::read(&file_path)?;
+            let total = contents.len();
+            let range = headers.get("Range").and_then(|r| RequestRange::try_from(r.as_str()).ok());
+
+            match range {
+                Some(range) => match range.resolve(total) {
+                    Some((start, end)) => {
+                        let slice = contents[start..=end].to_vec();
+                        Ok(Response::status(206, "Partial Content")
+                            .header("Content-Type", "application/octet-stream")
+                            .header("Accept-Ranges", "bytes")
+                            .header("Content-Range", &format!("bytes {start}-{end}/{total}"))
+                            .body(slice))
+                    },
+                    None => Ok(Response::status(416, "Range Not Satisfiable")
+                        .header("Content-Range", &format!("bytes */{total}"))
+                        .body(vec![]))
                 },
-                Err(_) => "HTTP/1.1 404 Not Found\r\n\r\n404 Not Found".to_string()
+                None => Ok(Response::status(200, "OK").header("Content-Type", "application/octet-stream").body(contents))
             }
         },
-        (Verb::Get, "/user-agent", _) => {
-            let user_agent = headers.get("User-Agent").unwrap(); // TODO: Handle
-            format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {0}\r\n\r\n{1}", user_agent.len(), user_agent)
-        },
-        (Verb::Get, "/", _) => "HTTP/1.1 200 OK\r\n\r\n200 OK".to_string(),
-        _ => "HTTP/1.1 404 Not Found\r\n\r\n404 Not Found".to_string()
-    };
+        Err(_) => Ok(Response::status(404, "Not Found").body(b"404 Not Found".to_vec()))
+    }
+}
+
+fn user_agent_handler(request: &Request, _params: &Params, _opts: &Args) -> Result<Response> {
+    let Request(_, headers, _) = request;
+    let user_agent = headers.get("User-Agent").unwrap(); // TODO: Handle
+    Ok(Response::status(200, "OK").header("Content-Type", "text/plain").body(user_agent.into_bytes()))
+}
+
+fn root_handler(_request: &Request, _params: &Params, _opts: &Args) -> Result<Response> {
+    Ok(Response::status(200, "OK").body(b"200 OK".to_vec()))
+}
+
+fn build_response(request: Request, opts: Args, router: &Router) -> Result<Response> {
+    let accept_encoding = request.1.get("Accept-Encoding");
+    let mut response = router.dispatch(&request, &opts)?;
+
+    // 206 bodies are a byte-exact slice described by Content-Range; compressing them would
+    // make the advertised range undecodable, so leave partial-content responses untouched.
+    let coding = accept_encoding.as_deref().and_then(negotiate_encoding).filter(|_| response.status != 206);
+    if let Some(coding) = coding {
+        response.body = Body(gzip_compress(&response.body.0)?);
+        response.headers.add(Header { key: "Content-Encoding".to_string(), value: coding.to_string() });
+    }
 
     Ok(response)
 }
 
+/// Serves requests off a single connection until the client sends `Connection: close`
+/// or the socket reaches EOF, as HTTP/1.1's default keep-alive requires.
+fn handle_connection(stream: TcpStream, opts: Args) {
+    let mut reader = BufReader::new(stream);
+    let router = Router::new();
+
+    loop {
+        let request = match read_stream(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(e) => {
+                eprintln!("Issue reading request, {0}", e);
+                break;
+            }
+        };
+
+        let client_requested_close = request.1.get("Connection")
+            .map(|value| value.eq_ignore_ascii_case("close"))
+            .unwrap_or(false);
+
+        let mut response = match build_response(request, opts.clone(), &router) {
+            Ok(response) => response,
+            Err(e) => {
+                eprintln!("Issue processing connection, {0}", e);
+                Response::status(500, "Internal Server Error").body(b"500 Internal Server Error".to_vec())
+            }
+        };
+
+        if client_requested_close {
+            response.headers.add(Header { key: "Connection".to_string(), value: "close".to_string() });
+        }
+
+        if let Err(e) = reader.get_mut().write_all(&response.serialize()) {
+            eprintln!("Issue writing response, {0}", e);
+            break;
+        }
+        let _ = reader.get_mut().flush();
+
+        if client_requested_close {
+            break;
+        }
+    }
+}
+
 #[derive(Clone)]
 struct Args {
     directory: Option<PathBuf>
@@ -234,20 +620,9 @@ fn main() -> Result<()> {
 
     for stream in listener.incoming() {
         match stream {
-            Ok(mut stream) => {
+            Ok(stream) => {
                 let im_being_lazy = args.clone();
-                thread::spawn(move || {
-                    match handle_request(&mut stream, im_being_lazy) {
-                        Ok(response) => { 
-                            stream.write_all(response.as_bytes()).unwrap();
-                        },
-                        Err(e) => { 
-                            stream.write_all(b"HTTP/1.1 500 Internal Server Error\r\n\r\n500 Internal Server Error");
-                            eprintln!("Issue processing connection, {0}", e);
-                        } 
-                    }
-                    stream.flush().unwrap();
-                });
+                thread::spawn(move || handle_connection(stream, im_being_lazy));
             }
             Err(e) => {
                 println!("error: {}", e);